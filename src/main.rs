@@ -1,20 +1,52 @@
-use std::{collections::HashMap, fs::canonicalize, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::canonicalize,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
 
 use anyhow::Result;
+use ignore::WalkBuilder;
 use lsp_server::{Connection, ExtractError, Message, Notification, Request, RequestId, Response};
 use lsp_types::{
-    request::GotoDefinition, Diagnostic, DiagnosticSeverity, DidChangeTextDocumentParams,
-    DidOpenTextDocumentParams, GotoDefinitionResponse, InitializeParams, OneOf, Position, Range,
-    ServerCapabilities, TextDocumentSyncKind, Url,
+    request::{Completion, DocumentSymbolRequest, GotoDefinition, HoverRequest},
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionResponse, Diagnostic,
+    DiagnosticSeverity, DidChangeTextDocumentParams, DidChangeWatchedFilesParams,
+    DidChangeWatchedFilesRegistrationOptions, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, DocumentSymbol, DocumentSymbolResponse, FileChangeType,
+    FileSystemWatcher, GlobPattern, GotoDefinitionResponse, Hover, HoverContents,
+    HoverProviderCapability, InitializeParams, MarkupContent, MarkupKind, OneOf, Position, Range,
+    Registration, RegistrationParams, ServerCapabilities, SymbolKind,
+    TextDocumentContentChangeEvent, TextDocumentSyncKind, Url, WorkspaceFolder,
 };
 use nom::{
-    bytes::complete::tag,
+    bytes::complete::{tag, take_while1},
     character::complete::{alphanumeric1, char, multispace1, not_line_ending},
-    combinator::opt,
+    combinator::{not, opt},
     multi::count,
-    sequence::{preceded, terminated},
+    sequence::{pair, preceded, terminated},
     IResult,
 };
+use ropey::Rope;
+
+/// Finds the line range of the `include:` section of a grlx file, i.e.
+/// from the `include:` header up to (but excluding) the `steps:` header.
+/// Returns an empty range if no `include:` header is present.
+///
+/// # Arguements
+/// * `input` - The string to search
+/// # Returns
+/// The half-open line-number range of the include section
+fn include_section_range(input: &str) -> std::ops::Range<usize> {
+    let mut lines = input.lines().enumerate();
+    let Some((start, _)) = lines.find(|&(_, line)| line.starts_with("include:")) else {
+        return 0..0;
+    };
+    let end = lines
+        .find(|&(_, line)| line.starts_with("steps:"))
+        .map_or_else(|| input.lines().count(), |(line_number, _)| line_number);
+    start..end
+}
 
 /// Parse the includes from a grlx file
 ///
@@ -23,11 +55,11 @@ use nom::{
 /// # Returns
 /// A HashMap of the line number and the path being referenced
 fn parse_includes_map(input: &str, base_path: PathBuf) -> HashMap<usize, PathBuf> {
+    let range = include_section_range(input);
     input
         .lines()
         .enumerate()
-        .skip_while(|&(_, line)| !line.starts_with("include:"))
-        .take_while(|&(_, line)| !line.starts_with("steps:"))
+        .filter(|&(line_number, _)| range.contains(&line_number))
         .filter_map(|(line_number, line)| {
             let line = parse_include_line(line);
             if let Ok(line) = line.as_ref() {
@@ -74,28 +106,272 @@ fn parse_current(input: &str) -> IResult<&str, Option<&str>> {
     ))(input)
 }
 
+/// Parses a top-level `steps:` entry: a state ID at exactly two-space
+/// indent, e.g. `  apache_installed:`.
+///
+/// # Arguements
+/// * `input` - The line to parse
+/// # Returns
+/// The state ID
+fn parse_state_id_line(input: &str) -> IResult<&str, &str> {
+    preceded(
+        // Exactly two spaces of indent, not more
+        pair(count(char(' '), 2), not(char(' '))),
+        terminated(
+            take_while1(|c: char| c.is_alphanumeric() || c == '_'),
+            char(':'),
+        ),
+    )(input)
+}
+
+/// Parses a nested state-function line under a `steps:` entry, e.g.
+/// `    pkg.installed:`, at exactly four-space indent.
+///
+/// # Arguements
+/// * `input` - The line to parse
+/// # Returns
+/// The state function name
+fn parse_state_function_line(input: &str) -> IResult<&str, &str> {
+    preceded(
+        // Exactly four spaces of indent, not more
+        pair(count(char(' '), 4), not(char(' '))),
+        terminated(
+            take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '.'),
+            char(':'),
+        ),
+    )(input)
+}
+
+/// Builds the document symbol outline for a grlx file's `steps:` block:
+/// each top-level state ID becomes an `OBJECT` symbol, and each nested
+/// state-function line (e.g. `pkg.installed`) becomes a `METHOD` child
+/// symbol with its `selection_range` pointing at the function token.
+///
+/// # Arguements
+/// * `input` - The full text of the document
+/// # Returns
+/// The nested document symbols for the `steps:` block
+#[allow(deprecated)]
+fn parse_steps_symbols(input: &str) -> Vec<DocumentSymbol> {
+    let lines: Vec<&str> = input.lines().collect();
+    let Some(steps_start) = lines.iter().position(|line| line.starts_with("steps:")) else {
+        return Vec::new();
+    };
+
+    let mut symbols: Vec<DocumentSymbol> = Vec::new();
+    let mut current: Option<DocumentSymbol> = None;
+
+    for (line_number, line) in lines.iter().enumerate().skip(steps_start + 1) {
+        let line_number = line_number as u32;
+        let line_range = Range::new(
+            Position::new(line_number, 0),
+            Position::new(line_number, line.chars().count() as u32),
+        );
+
+        if let Ok((_, state_id)) = parse_state_id_line(line) {
+            if let Some(symbol) = current.take() {
+                symbols.push(symbol);
+            }
+            current = Some(DocumentSymbol {
+                name: state_id.to_string(),
+                detail: None,
+                kind: SymbolKind::OBJECT,
+                tags: None,
+                deprecated: None,
+                range: line_range,
+                selection_range: line_range,
+                children: Some(Vec::new()),
+            });
+            continue;
+        }
+
+        if let Ok((_, function)) = parse_state_function_line(line) {
+            let Some(parent) = current.as_mut() else {
+                continue;
+            };
+            // The parser only accepts exactly four spaces of indent, so the
+            // function token always starts at column 4.
+            let start = 4;
+            let selection_range = Range::new(
+                Position::new(line_number, start),
+                Position::new(line_number, start + function.chars().count() as u32),
+            );
+            let child = DocumentSymbol {
+                name: function.to_string(),
+                detail: None,
+                kind: SymbolKind::METHOD,
+                tags: None,
+                deprecated: None,
+                range: line_range,
+                selection_range,
+                children: None,
+            };
+            parent
+                .children
+                .get_or_insert_with(Vec::new)
+                .push(child);
+        }
+
+        // Any line within a state ID's block (functions and their nested
+        // details) widens the parent's range so it contains its children,
+        // as LSP clients require for outline nesting and breadcrumbs.
+        if let Some(parent) = current.as_mut() {
+            parent.range.end = line_range.end;
+        }
+    }
+    if let Some(symbol) = current.take() {
+        symbols.push(symbol);
+    }
+    symbols
+}
+
+/// Computes the character range of the include token on a source line,
+/// spanning from the start of the referenced name to the end of the line.
+/// Falls back to the whole line when the line no longer parses as an
+/// include (e.g. it changed out from under a stale line number).
+///
+/// # Arguements
+/// * `source_line` - The raw text of the line the include sits on
+/// # Returns
+/// The start and end character offsets of the include token
+fn include_token_columns(source_line: &str) -> (u32, u32) {
+    let end = source_line.chars().count() as u32;
+    let start = parse_include_line(source_line)
+        .map(|(_, token)| end.saturating_sub(token.chars().count() as u32))
+        .unwrap_or(0);
+    (start, end)
+}
+
+/// Resolves an include map entry to the file it actually points at: the
+/// entry itself if it exists, or its directory-with-`init.grlx` fallback
+/// (the same resolution goto-definition and hover both need) otherwise.
+/// Returns `None` when neither form exists.
+///
+/// # Arguements
+/// * `path` - The include map entry, e.g. `.../apache.grlx`
+/// # Returns
+/// The canonicalized path actually being referenced
+fn resolve_include_target(path: &Path) -> Option<PathBuf> {
+    let dir_path = path.parent()?.join(path.file_stem()?);
+    let resolved = if path.exists() {
+        path
+    } else if dir_path.exists() {
+        &dir_path
+    } else {
+        return None;
+    };
+    let mut complete_path = canonicalize(resolved).ok()?;
+    if complete_path.is_dir() {
+        complete_path = complete_path.join("init.grlx");
+    }
+    Some(complete_path)
+}
+
+/// Builds the markdown hover body for an include target: its path,
+/// whether it exists, and a fenced preview of the first handful of its
+/// non-empty lines.
+///
+/// # Arguements
+/// * `target` - The include target, as returned by
+///   `resolve_include_target`, or the raw include map entry when it does
+///   not resolve
+/// # Returns
+/// The markdown hover contents
+fn include_hover_markdown(target: &Path) -> String {
+    let exists = target.exists();
+    let mut value = format!(
+        "`{}`\n\n{}",
+        target.display(),
+        if exists {
+            "File exists"
+        } else {
+            "File does not exist"
+        }
+    );
+    if exists {
+        if let Ok(file) = std::fs::File::open(target) {
+            let preview: Vec<String> = BufReader::new(file)
+                .lines()
+                .map_while(Result::ok)
+                .filter(|line| !line.trim().is_empty())
+                .take(5)
+                .collect();
+            if !preview.is_empty() {
+                value.push_str(&format!("\n\n```grlx\n{}\n```", preview.join("\n")));
+            }
+        }
+    }
+    value
+}
+
+/// Lists the state modules resolvable from an `include:` entry in
+/// `base_path`: sibling `*.grlx` files (extension stripped) and
+/// subdirectories containing an `init.grlx` (offered by directory name).
+/// This mirrors the file/directory-with-init resolution goto-definition
+/// already does.
+///
+/// # Arguements
+/// * `base_path` - The directory the including file lives in
+/// # Returns
+/// Completion items for every resolvable state module
+fn include_completions(base_path: &Path) -> Vec<CompletionItem> {
+    let Ok(entries) = std::fs::read_dir(base_path) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+            if path.is_dir() {
+                if !path.join("init.grlx").exists() {
+                    return None;
+                }
+                let label = path.file_name()?.to_str()?.to_string();
+                Some(CompletionItem {
+                    label,
+                    kind: Some(CompletionItemKind::MODULE),
+                    ..Default::default()
+                })
+            } else {
+                if path.extension().and_then(|ext| ext.to_str()) != Some("grlx") {
+                    return None;
+                }
+                let label = path.file_stem()?.to_str()?.to_string();
+                Some(CompletionItem {
+                    label,
+                    kind: Some(CompletionItemKind::FILE),
+                    ..Default::default()
+                })
+            }
+        })
+        .collect()
+}
+
 /// Generates a diagnostic for a missing file
 ///
 /// # Arguements
 /// * `file_name` - The name of the file
 /// * `line` - The line number the missing file is on
+/// * `source_line` - The raw text of the include line, used to underline
+///   the actual include token instead of a fixed column
 /// # Returns
 /// A Diagnostic for the missing file
-fn missing_file_diagnostic(file_name: PathBuf, line: u32) -> Diagnostic {
+fn missing_file_diagnostic(file_name: PathBuf, line: u32, source_line: &str) -> Diagnostic {
     let name: String = file_name
         .file_name()
         .and_then(|name| name.to_str())
         .map(|name| name.to_string())
         .unwrap();
+    let (start_character, end_character) = include_token_columns(source_line);
     Diagnostic {
         range: Range {
             start: Position {
                 line,
-                character: 200,
+                character: start_character,
             },
             end: Position {
                 line,
-                character: 200,
+                character: end_character,
             },
         },
         severity: Some(DiagnosticSeverity::ERROR),
@@ -114,12 +390,16 @@ fn missing_file_diagnostic(file_name: PathBuf, line: u32) -> Diagnostic {
 /// * `connection` - The connection to the client
 /// * `files` - The files HashMap
 /// * `file_name` - The name of the file
+/// * `source` - The current full text of `file_name`, used to underline
+///   the real include token for each missing-file diagnostic
 fn generate_diagnostics(
     connection: &Connection,
     files: &HashMap<String, HashMap<usize, PathBuf>>,
     file_name: Url,
+    source: &str,
 ) -> Result<()> {
     let name = file_name.to_string();
+    let source_lines: Vec<&str> = source.lines().collect();
     let diagnostics = files
         .get(&name)
         .unwrap()
@@ -133,23 +413,26 @@ fn generate_diagnostics(
             // if dir.exists() {
             //     return None;
             // }
-            Some(missing_file_diagnostic(file_name.clone(), *line as u32))
+            let source_line = source_lines.get(*line).copied().unwrap_or("");
+            Some(missing_file_diagnostic(
+                file_name.clone(),
+                *line as u32,
+                source_line,
+            ))
         })
         .collect::<Vec<_>>();
-    if !diagnostics.is_empty() {
-        let notification = lsp_types::PublishDiagnosticsParams {
-            uri: file_name,
-            diagnostics,
-            version: None,
-        };
-        let notification = Notification {
-            method: "textDocument/publishDiagnostics".to_string(),
-            params: serde_json::to_value(notification).unwrap(),
-        };
-        connection
-            .sender
-            .send(Message::Notification(notification))?;
-    }
+    let notification = lsp_types::PublishDiagnosticsParams {
+        uri: file_name,
+        diagnostics,
+        version: None,
+    };
+    let notification = Notification {
+        method: "textDocument/publishDiagnostics".to_string(),
+        params: serde_json::to_value(notification).unwrap(),
+    };
+    connection
+        .sender
+        .send(Message::Notification(notification))?;
     Ok(())
 }
 
@@ -162,7 +445,7 @@ fn generate_diagnostics(
 fn update_files(
     files: &mut HashMap<String, HashMap<usize, PathBuf>>,
     file_name: Url,
-    file: String,
+    file: &str,
 ) {
     let base = file_name
         .to_file_path()
@@ -170,16 +453,226 @@ fn update_files(
         .parent()
         .unwrap()
         .to_path_buf();
-    let includes = parse_includes_map(&file, base);
+    let includes = parse_includes_map(file, base);
     files.insert(file_name.to_string(), includes);
 }
 
+/// Checks whether an include map entry resolves to `changed_path`, either
+/// directly or via the directory-with-`init.grlx` fallback goto-definition
+/// already uses.
+///
+/// # Arguements
+/// * `entry_path` - The path an include map entry points at
+/// * `changed_path` - The path reported by a file-watcher event
+/// # Returns
+/// Whether `entry_path` refers to `changed_path`
+fn references_path(entry_path: &Path, changed_path: &Path) -> bool {
+    if entry_path == changed_path {
+        return true;
+    }
+    let Some(parent) = entry_path.parent() else {
+        return false;
+    };
+    let Some(stem) = entry_path.file_stem() else {
+        return false;
+    };
+    parent.join(stem).join("init.grlx") == changed_path
+}
+
+/// Finds every indexed document whose include map references
+/// `changed_path`, so it can be re-validated after the file is created or
+/// deleted on disk.
+///
+/// # Arguements
+/// * `files` - The files HashMap
+/// * `changed_path` - The path reported by a file-watcher event
+/// # Returns
+/// The URIs (as stored in `files`) of the affected documents
+fn affected_documents(
+    files: &HashMap<String, HashMap<usize, PathBuf>>,
+    changed_path: &Path,
+) -> Vec<String> {
+    files
+        .iter()
+        .filter(|(_, includes)| {
+            includes
+                .values()
+                .any(|entry_path| references_path(entry_path, changed_path))
+        })
+        .map(|(uri, _)| uri.clone())
+        .collect()
+}
+
+/// Reports whether the client advertised support for dynamically
+/// registering capabilities after initialization (the mechanism
+/// `register_file_watcher` relies on). Several lightweight/embedded LSP
+/// clients never set this, and sending `client/registerCapability`
+/// regardless risks the client rejecting or silently ignoring it.
+///
+/// # Arguements
+/// * `params` - The `InitializeParams` received during the handshake
+fn supports_dynamic_watch_registration(params: &InitializeParams) -> bool {
+    params
+        .capabilities
+        .workspace
+        .as_ref()
+        .and_then(|workspace| workspace.did_change_watched_files.as_ref())
+        .and_then(|watched_files| watched_files.dynamic_registration)
+        .unwrap_or(false)
+}
+
+/// Registers a dynamic `workspace/didChangeWatchedFiles` watcher for
+/// `**/*.grlx` so the server is notified when an included file is created
+/// or deleted on disk, even if it is never opened in the editor.
+///
+/// Callers should check [`supports_dynamic_watch_registration`] first;
+/// this function assumes the client accepts the registration.
+///
+/// # Arguements
+/// * `connection` - The connection to the client
+fn register_file_watcher(connection: &Connection) -> Result<()> {
+    let registration = Registration {
+        id: "grlx-lsp-watch-grlx-files".to_string(),
+        method: "workspace/didChangeWatchedFiles".to_string(),
+        register_options: Some(serde_json::to_value(
+            DidChangeWatchedFilesRegistrationOptions {
+                watchers: vec![FileSystemWatcher {
+                    glob_pattern: GlobPattern::String("**/*.grlx".to_string()),
+                    kind: None,
+                }],
+            },
+        )?),
+    };
+    let request = Request {
+        id: RequestId::from(0),
+        method: "client/registerCapability".to_string(),
+        params: serde_json::to_value(RegistrationParams {
+            registrations: vec![registration],
+        })?,
+    };
+    connection.sender.send(Message::Request(request))?;
+    Ok(())
+}
+
+/// Converts an LSP `Position` to a `ropey` char index. LSP columns are
+/// UTF-16 code units, not chars, so `character` is converted via the
+/// line's own UTF-16-to-char table rather than used as a char offset
+/// directly; otherwise any non-ASCII content before the position on that
+/// line would miscompute the splice point.
+///
+/// A client can send a stale or malformed position (a line past the end
+/// of the document, or a UTF-16 offset past the end of the line) — e.g.
+/// if its own buffer has drifted out of sync with ours. Rather than
+/// trusting it and panicking on an out-of-bounds `line`/`utf16_cu_to_char`
+/// call, this clamps to the nearest valid position.
+///
+/// # Arguements
+/// * `rope` - The rope the position is relative to
+/// * `position` - The LSP position to convert
+/// # Returns
+/// The char index into `rope`, clamped to a valid range
+fn lsp_position_to_char(rope: &Rope, position: Position) -> usize {
+    let line = (position.line as usize).min(rope.len_lines().saturating_sub(1));
+    let line_start = rope.try_line_to_char(line).unwrap_or_else(|_| rope.len_chars());
+    let line_slice = rope.line(line);
+    let char_offset_in_line = line_slice
+        .try_utf16_cu_to_char(position.character as usize)
+        .unwrap_or_else(|_| line_slice.len_chars());
+    line_start + char_offset_in_line
+}
+
+/// Applies a single `TextDocumentContentChangeEvent` to a rope buffer,
+/// splicing a ranged edit in place or replacing the whole buffer when the
+/// client sends a full-text change.
+///
+/// # Arguements
+/// * `rope` - The rope to update in place
+/// * `change` - The content change event to apply
+fn apply_change(rope: &mut Rope, change: &TextDocumentContentChangeEvent) {
+    match change.range {
+        Some(range) => {
+            let start = lsp_position_to_char(rope, range.start);
+            let end = lsp_position_to_char(rope, range.end);
+            let (start, end) = (start.min(end), start.max(end));
+            rope.remove(start..end);
+            rope.insert(start, &change.text);
+        }
+        None => *rope = Rope::from_str(&change.text),
+    }
+}
+
+
+/// Resolves the workspace root paths to index at startup, preferring
+/// `workspace_folders` and falling back to the deprecated `root_uri`.
+#[allow(deprecated)]
+fn workspace_roots(params: &InitializeParams) -> Vec<PathBuf> {
+    if let Some(folders) = &params.workspace_folders {
+        return folders
+            .iter()
+            .filter_map(|folder: &WorkspaceFolder| folder.uri.to_file_path().ok())
+            .collect();
+    }
+    params
+        .root_uri
+        .as_ref()
+        .and_then(|uri| uri.to_file_path().ok())
+        .into_iter()
+        .collect()
+}
+
+/// Eagerly walks the workspace with `ignore::WalkBuilder` (honoring
+/// `.gitignore`), seeding `files` with every `*.grlx` file's include map
+/// and publishing diagnostics for each one, so the problem panel is
+/// populated project-wide before the user opens anything.
+///
+/// # Arguements
+/// * `connection` - The connection to the client
+/// * `params` - The `InitializeParams` received during the handshake
+/// * `files` - The files HashMap to seed
+fn index_workspace(
+    connection: &Connection,
+    params: &InitializeParams,
+    files: &mut HashMap<String, HashMap<usize, PathBuf>>,
+) -> Result<()> {
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    for root in workspace_roots(params) {
+        for entry in WalkBuilder::new(&root).build() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    eprintln!("Error walking workspace: {:?}", err);
+                    continue;
+                }
+            };
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("grlx") {
+                continue;
+            }
+            if !seen.insert(path.to_path_buf()) {
+                continue;
+            }
+            let Ok(text) = std::fs::read_to_string(path) else {
+                continue;
+            };
+            let Ok(uri) = Url::from_file_path(path) else {
+                continue;
+            };
+            update_files(files, uri.clone(), &text);
+            generate_diagnostics(connection, files, uri, &text)?;
+        }
+    }
+    Ok(())
+}
 
 fn event_loop(connection: Connection, params: serde_json::Value) -> Result<()> {
     // For some reason, we must parse the params to allow for exiting
-    let _params: InitializeParams = serde_json::from_value(params).unwrap();
+    let params: InitializeParams = serde_json::from_value(params).unwrap();
     eprintln!("Starting main loop");
     let mut files: HashMap<String, HashMap<usize, PathBuf>> = HashMap::new();
+    // Rope buffers for currently-open documents, kept in sync via
+    // textDocument/didOpen and textDocument/didChange for incremental sync.
+    let mut documents: HashMap<String, Rope> = HashMap::new();
+    index_workspace(&connection, &params, &mut files)?;
     for msg in &connection.receiver { eprintln!("Connection received message {:?}", msg); match msg {
             Message::Request(req) => {
                 let method = req.method.as_str();
@@ -192,33 +685,18 @@ fn event_loop(connection: Connection, params: serde_json::Value) -> Result<()> {
                                     params.text_document_position_params.text_document.uri;
 
                                 if let Some(file) = files.get(&current_file.to_string()) {
-                                    // let path = current_file.to_file_path().unwrap().parent().unwrap();
                                     let position = params.text_document_position_params.position;
                                     let line = position.line as usize;
 
-                                    if let Some(mut path) = file.get(&line) {
-                                        let dir_path = PathBuf::from(path.file_stem().unwrap());
-                                        let dir_path =
-                                            path.parent().unwrap().join(dir_path.clone());
-                                        eprintln!("Dir Path: {}", dir_path.display());
-                                        if !path.exists() {
-                                            eprintln!("Path {} does not exist", path.display());
-                                            if !dir_path.exists() {
-                                                eprintln!(
-                                                    "Dir Path {} does not exist",
-                                                    dir_path.display()
-                                                );
-                                                continue;
-                                            } else {
-                                                path = &dir_path;
-                                            }
-                                        }
-                                        // This is a case where we are actually referencing a file in the
-                                        // same directory as the current file.
-                                        let mut complete_path = canonicalize(path)?;
-                                        if complete_path.is_dir() {
-                                            complete_path = complete_path.join("init.grlx");
-                                        }
+                                    if let Some(path) = file.get(&line) {
+                                        let Some(complete_path) = resolve_include_target(path)
+                                        else {
+                                            eprintln!(
+                                                "Path {} does not exist",
+                                                path.display()
+                                            );
+                                            continue;
+                                        };
                                         let final_path =
                                             lsp_types::Url::from_file_path(complete_path).unwrap();
                                         eprintln!("Path: {}", final_path);
@@ -247,6 +725,115 @@ fn event_loop(connection: Connection, params: serde_json::Value) -> Result<()> {
                             Err(ExtractError::MethodMismatch(req)) => req,
                         };
                     }
+                    "textDocument/completion" => {
+                        match cast::<Completion>(req) {
+                            Ok((id, params)) => {
+                                eprintln!("Received completion request {:?}", params);
+                                let current_file = params.text_document_position.text_document.uri;
+                                let position = params.text_document_position.position;
+
+                                let mut items = Vec::new();
+                                if let Some(rope) = documents.get(&current_file.to_string()) {
+                                    let line_number = position.line as usize;
+                                    let source = rope.to_string();
+                                    if include_section_range(&source).contains(&line_number) {
+                                        if let Some(line) = rope.get_line(line_number) {
+                                            let line = line.to_string();
+                                            if parse_include_line(&line).is_ok() {
+                                                if let Ok(base) = current_file.to_file_path() {
+                                                    if let Some(base) = base.parent() {
+                                                        items = include_completions(base);
+                                                    }
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+
+                                let result = CompletionResponse::Array(items);
+                                let result = serde_json::to_value(&result).unwrap();
+                                let response = Response {
+                                    id,
+                                    result: Some(result),
+                                    error: None,
+                                };
+                                connection.sender.send(Message::Response(response))?;
+
+                                continue;
+                            }
+                            Err(err @ ExtractError::JsonError { .. }) => panic!("{err:?}"),
+                            Err(ExtractError::MethodMismatch(req)) => req,
+                        };
+                    }
+                    "textDocument/documentSymbol" => {
+                        match cast::<DocumentSymbolRequest>(req) {
+                            Ok((id, params)) => {
+                                eprintln!("Received document symbol request {:?}", params);
+                                let current_file = params.text_document.uri;
+
+                                let source = match documents.get(&current_file.to_string()) {
+                                    Some(rope) => rope.to_string(),
+                                    None => current_file
+                                        .to_file_path()
+                                        .ok()
+                                        .and_then(|path| std::fs::read_to_string(path).ok())
+                                        .unwrap_or_default(),
+                                };
+
+                                let symbols = parse_steps_symbols(&source);
+                                let result = DocumentSymbolResponse::Nested(symbols);
+                                let result = serde_json::to_value(&result).unwrap();
+                                let response = Response {
+                                    id,
+                                    result: Some(result),
+                                    error: None,
+                                };
+                                connection.sender.send(Message::Response(response))?;
+
+                                continue;
+                            }
+                            Err(err @ ExtractError::JsonError { .. }) => panic!("{err:?}"),
+                            Err(ExtractError::MethodMismatch(req)) => req,
+                        };
+                    }
+                    "textDocument/hover" => {
+                        match cast::<HoverRequest>(req) {
+                            Ok((id, params)) => {
+                                eprintln!("Received hover request {:?}", params);
+                                let current_file =
+                                    params.text_document_position_params.text_document.uri;
+                                let position = params.text_document_position_params.position;
+                                let line = position.line as usize;
+
+                                let hover = files
+                                    .get(&current_file.to_string())
+                                    .and_then(|file| file.get(&line))
+                                    .map(|path| {
+                                        let target = resolve_include_target(path)
+                                            .unwrap_or_else(|| path.clone());
+                                        Hover {
+                                            contents: HoverContents::Markup(MarkupContent {
+                                                kind: MarkupKind::Markdown,
+                                                value: include_hover_markdown(&target),
+                                            }),
+                                            range: None,
+                                        }
+                                    });
+
+                                let result = serde_json::to_value(&hover).unwrap();
+                                let response = Response {
+                                    id,
+                                    result: Some(result),
+                                    error: None,
+                                };
+                                connection.sender.send(Message::Response(response))?;
+
+                                continue;
+                            }
+                            Err(err @ ExtractError::JsonError { .. }) => panic!("{err:?}"),
+                            Err(ExtractError::MethodMismatch(req)) => req,
+                        };
+                    }
                     _ => {}
                 }
                 // TODO: We need to handle multiple potential cases
@@ -263,21 +850,68 @@ fn event_loop(connection: Connection, params: serde_json::Value) -> Result<()> {
                         if let Ok(params) =
                             serde_json::from_value::<DidOpenTextDocumentParams>(resp.params)
                         {
-                            let file = params.text_document.text;
                             let file_name = params.text_document.uri;
-                            update_files(&mut files, file_name.clone(), file.to_string());
-                            generate_diagnostics(&connection, &files, file_name)?;
+                            let rope = Rope::from_str(&params.text_document.text);
+                            let text = rope.to_string();
+                            documents.insert(file_name.to_string(), rope);
+                            update_files(&mut files, file_name.clone(), &text);
+                            generate_diagnostics(&connection, &files, file_name, &text)?;
                         }
                     }
                     "textDocument/didChange" => {
                         if let Ok(params) =
                             serde_json::from_value::<DidChangeTextDocumentParams>(resp.params)
                         {
-                            let changes = params.content_changes;
-                            let file = &changes[0].text;
                             let file_name = params.text_document.uri;
-                            update_files(&mut files, file_name.clone(), file.to_string());
-                            generate_diagnostics(&connection, &files, file_name)?;
+                            if let Some(rope) = documents.get_mut(&file_name.to_string()) {
+                                for change in &params.content_changes {
+                                    apply_change(rope, change);
+                                }
+                                let text = rope.to_string();
+                                update_files(&mut files, file_name.clone(), &text);
+                                generate_diagnostics(&connection, &files, file_name, &text)?;
+                            }
+                        }
+                    }
+                    "textDocument/didClose" => {
+                        if let Ok(params) =
+                            serde_json::from_value::<DidCloseTextDocumentParams>(resp.params)
+                        {
+                            // Remove only the live rope buffer; keep the include map in
+                            // `files` so cross-file diagnostics and goto-definition
+                            // against this file keep working while it's closed.
+                            documents.remove(&params.text_document.uri.to_string());
+                        }
+                    }
+                    "workspace/didChangeWatchedFiles" => {
+                        if let Ok(params) =
+                            serde_json::from_value::<DidChangeWatchedFilesParams>(resp.params)
+                        {
+                            for change in params.changes {
+                                if !matches!(
+                                    change.typ,
+                                    FileChangeType::CREATED | FileChangeType::DELETED
+                                ) {
+                                    continue;
+                                }
+                                let Ok(changed_path) = change.uri.to_file_path() else {
+                                    continue;
+                                };
+                                for uri in affected_documents(&files, &changed_path) {
+                                    let Ok(url) = Url::parse(&uri) else {
+                                        continue;
+                                    };
+                                    let source = match documents.get(&uri) {
+                                        Some(rope) => rope.to_string(),
+                                        None => url
+                                            .to_file_path()
+                                            .ok()
+                                            .and_then(|path| std::fs::read_to_string(path).ok())
+                                            .unwrap_or_default(),
+                                    };
+                                    generate_diagnostics(&connection, &files, url, &source)?;
+                                }
+                            }
                         }
                     }
                     _ => {}
@@ -295,14 +929,30 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let (connection, io_threads) = Connection::stdio();
     let server_capabilities = serde_json::to_value(ServerCapabilities {
         text_document_sync: Some(lsp_types::TextDocumentSyncCapability::Kind(
-            TextDocumentSyncKind::FULL,
+            TextDocumentSyncKind::INCREMENTAL,
         )),
         definition_provider: Some(OneOf::Left(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
         ..Default::default()
     })
     .unwrap();
 
     let init_params = connection.initialize(server_capabilities).unwrap();
+    // `Connection::initialize` already performs the full init handshake,
+    // including blocking on the client's "initialized" notification before
+    // returning, so the watcher can be registered as soon as it returns.
+    let typed_init_params: InitializeParams =
+        serde_json::from_value(init_params.clone()).unwrap();
+    if supports_dynamic_watch_registration(&typed_init_params) {
+        register_file_watcher(&connection)?;
+    } else {
+        eprintln!(
+            "client did not advertise workspace.didChangeWatchedFiles.dynamicRegistration; \
+             skipping watcher registration, include create/delete revalidation is disabled"
+        );
+    }
     event_loop(connection, init_params)?;
     io_threads.join()?;
 
@@ -341,4 +991,248 @@ mod tests {
         let result = parse_current(input);
         assert!(result.unwrap().1.is_none());
     }
+
+    #[test]
+    fn supports_dynamic_watch_registration_true_when_advertised() {
+        let params = InitializeParams {
+            capabilities: lsp_types::ClientCapabilities {
+                workspace: Some(lsp_types::WorkspaceClientCapabilities {
+                    did_change_watched_files: Some(
+                        lsp_types::DidChangeWatchedFilesClientCapabilities {
+                            dynamic_registration: Some(true),
+                            relative_pattern_support: None,
+                        },
+                    ),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(supports_dynamic_watch_registration(&params));
+    }
+
+    #[test]
+    fn supports_dynamic_watch_registration_false_when_absent() {
+        let params = InitializeParams::default();
+        assert!(!supports_dynamic_watch_registration(&params));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn workspace_roots_prefers_workspace_folders() {
+        let params = InitializeParams {
+            root_uri: Some(Url::from_file_path("/tmp/root-uri").unwrap()),
+            workspace_folders: Some(vec![WorkspaceFolder {
+                uri: Url::from_file_path("/tmp/folder-a").unwrap(),
+                name: "folder-a".to_string(),
+            }]),
+            ..Default::default()
+        };
+        let roots = workspace_roots(&params);
+        assert_eq!(roots, vec![PathBuf::from("/tmp/folder-a")]);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn workspace_roots_falls_back_to_root_uri() {
+        let params = InitializeParams {
+            root_uri: Some(Url::from_file_path("/tmp/root-uri").unwrap()),
+            ..Default::default()
+        };
+        let roots = workspace_roots(&params);
+        assert_eq!(roots, vec![PathBuf::from("/tmp/root-uri")]);
+    }
+
+    #[test]
+    fn include_token_columns_spans_name_to_end_of_line() {
+        let input = "  - .apache";
+        let (start, end) = include_token_columns(input);
+        assert_eq!(start, 4);
+        assert_eq!(end, input.chars().count() as u32);
+    }
+
+    #[test]
+    fn include_token_columns_falls_back_to_whole_line() {
+        let input = "not an include line";
+        let (start, end) = include_token_columns(input);
+        assert_eq!(start, 0);
+        assert_eq!(end, input.chars().count() as u32);
+    }
+
+    #[test]
+    fn apply_change_splices_a_ranged_edit() {
+        let mut rope = Rope::from_str("include:\n  - .apache\nsteps:\n");
+        let change = TextDocumentContentChangeEvent {
+            range: Some(Range {
+                start: Position {
+                    line: 1,
+                    character: 4,
+                },
+                end: Position {
+                    line: 1,
+                    character: 11,
+                },
+            }),
+            range_length: None,
+            text: ".nginx".to_string(),
+        };
+        apply_change(&mut rope, &change);
+        assert_eq!(rope.to_string(), "include:\n  - .nginx\nsteps:\n");
+    }
+
+    #[test]
+    fn apply_change_splices_past_a_multibyte_character() {
+        // "café" has a 2-byte UTF-8, 1-UTF-16-code-unit 'é' at column 3, so
+        // naive char-offset math would happen to agree with UTF-16 here;
+        // use a character outside the BMP ('😀', 2 UTF-16 code units but 1
+        // char) to actually distinguish the two.
+        let mut rope = Rope::from_str("  - .😀nginx\n");
+        let change = TextDocumentContentChangeEvent {
+            range: Some(Range {
+                start: Position {
+                    line: 0,
+                    // "  - ." is 5 UTF-16 code units, then the astral
+                    // emoji takes 2 more, landing right after it.
+                    character: 7,
+                },
+                end: Position {
+                    line: 0,
+                    character: 7,
+                },
+            }),
+            range_length: None,
+            text: "!".to_string(),
+        };
+        apply_change(&mut rope, &change);
+        assert_eq!(rope.to_string(), "  - .😀!nginx\n");
+    }
+
+    #[test]
+    fn lsp_position_to_char_clamps_out_of_range_position() {
+        let rope = Rope::from_str("short\n");
+        // Neither the line nor the column exist in this document; this
+        // must clamp instead of panicking.
+        let index = lsp_position_to_char(&rope, Position::new(5, 999));
+        assert!(index <= rope.len_chars());
+    }
+
+    /// Returns a unique scratch directory under the OS temp dir for a test
+    /// to populate with fixture files, named after the test and the
+    /// current process so parallel test binaries never collide.
+    ///
+    /// # Arguements
+    /// * `test_name` - A name unique to the calling test
+    /// # Returns
+    /// The path of the (not yet created) scratch directory
+    fn test_scratch_dir(test_name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("grlx-lsp-test-{}-{}", std::process::id(), test_name))
+    }
+
+    #[test]
+    fn include_completions_lists_siblings_and_init_dirs() {
+        let dir = test_scratch_dir("include_completions_lists_siblings_and_init_dirs");
+        std::fs::create_dir_all(dir.join("nginx")).unwrap();
+        std::fs::write(dir.join("nginx").join("init.grlx"), "steps:\n").unwrap();
+        std::fs::write(dir.join("apache.grlx"), "steps:\n").unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a state").unwrap();
+
+        let mut labels: Vec<String> = include_completions(&dir)
+            .into_iter()
+            .map(|item| item.label)
+            .collect();
+        labels.sort();
+
+        assert_eq!(labels, vec!["apache".to_string(), "nginx".to_string()]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn references_path_matches_direct_and_init_targets() {
+        let entry = PathBuf::from("/states/apache.grlx");
+        assert!(references_path(&entry, &PathBuf::from("/states/apache.grlx")));
+        assert!(references_path(
+            &entry,
+            &PathBuf::from("/states/apache/init.grlx")
+        ));
+        assert!(!references_path(&entry, &PathBuf::from("/states/nginx.grlx")));
+    }
+
+    #[test]
+    fn affected_documents_finds_referencing_entries() {
+        let mut files: HashMap<String, HashMap<usize, PathBuf>> = HashMap::new();
+        let mut includes = HashMap::new();
+        includes.insert(1, PathBuf::from("/states/apache.grlx"));
+        files.insert("file:///states/top.grlx".to_string(), includes);
+        files.insert(
+            "file:///states/unrelated.grlx".to_string(),
+            HashMap::new(),
+        );
+
+        let affected = affected_documents(&files, &PathBuf::from("/states/apache/init.grlx"));
+        assert_eq!(affected, vec!["file:///states/top.grlx".to_string()]);
+    }
+
+    #[test]
+    fn parse_steps_symbols_builds_nested_outline() {
+        let input = "include:\n  - .apache\nsteps:\n  apache_installed:\n    pkg.installed:\n      - name: apache2\n  apache_running:\n    service.running:\n      - name: apache2\n";
+        let symbols = parse_steps_symbols(input);
+        assert_eq!(symbols.len(), 2);
+
+        assert_eq!(symbols[0].name, "apache_installed");
+        assert_eq!(symbols[0].kind, SymbolKind::OBJECT);
+        let children = symbols[0].children.as_ref().unwrap();
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].name, "pkg.installed");
+        assert_eq!(children[0].kind, SymbolKind::METHOD);
+        assert_eq!(children[0].selection_range.start.character, 4);
+        assert_eq!(symbols[0].range.start.line, 3);
+        assert_eq!(symbols[0].range.end.line, 5);
+
+        assert_eq!(symbols[1].name, "apache_running");
+        assert_eq!(
+            symbols[1].children.as_ref().unwrap()[0].name,
+            "service.running"
+        );
+        assert_eq!(symbols[1].range.start.line, 6);
+        assert_eq!(symbols[1].range.end.line, 8);
+    }
+
+    #[test]
+    fn resolve_include_target_prefers_direct_file_then_init_dir() {
+        let dir = test_scratch_dir("resolve_include_target_prefers_direct_file_then_init_dir");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("apache.grlx"), "steps:\n").unwrap();
+        std::fs::create_dir_all(dir.join("nginx")).unwrap();
+        std::fs::write(dir.join("nginx").join("init.grlx"), "steps:\n").unwrap();
+
+        let file_target = resolve_include_target(&dir.join("apache.grlx")).unwrap();
+        assert_eq!(file_target, canonicalize(dir.join("apache.grlx")).unwrap());
+
+        let dir_target = resolve_include_target(&dir.join("nginx.grlx")).unwrap();
+        assert_eq!(
+            dir_target,
+            canonicalize(dir.join("nginx")).unwrap().join("init.grlx")
+        );
+
+        assert!(resolve_include_target(&dir.join("missing.grlx")).is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn include_hover_markdown_previews_existing_file() {
+        let dir = test_scratch_dir("include_hover_markdown_previews_existing_file");
+        std::fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("apache.grlx");
+        std::fs::write(&target, "include:\n\nsteps:\n  apache_installed:\n").unwrap();
+
+        let markdown = include_hover_markdown(&target);
+        assert!(markdown.contains("File exists"));
+        assert!(markdown.contains("```grlx"));
+        assert!(markdown.contains("apache_installed:"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }